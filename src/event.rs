@@ -0,0 +1,31 @@
+//! Bevy-native translations of the events GGRS reports about a running session, so games can
+//! drive connection UI without reaching into the raw `ggrs` session resource.
+
+use ggrs::PlayerHandle;
+
+/// A session-lifecycle notification forwarded from `ggrs` each frame. See
+/// [`crate::GGRSPlugin`], which registers this as a Bevy event.
+#[derive(Debug, Clone, Copy)]
+pub enum GgrsEvent {
+    /// We're still exchanging sync packets with `player`; `count` out of `total` have
+    /// succeeded so far.
+    Synchronizing {
+        player: PlayerHandle,
+        total: u32,
+        count: u32,
+    },
+    /// `player` has finished synchronizing and is ready to play.
+    Synchronized { player: PlayerHandle },
+    /// `player` has disconnected.
+    Disconnected { player: PlayerHandle },
+    /// The connection to `player` has been interrupted and will be dropped in
+    /// `disconnect_timeout` milliseconds if it doesn't recover.
+    NetworkInterrupted {
+        player: PlayerHandle,
+        disconnect_timeout: u128,
+    },
+    /// A previously interrupted connection to `player` has resumed.
+    NetworkResumed { player: PlayerHandle },
+    /// GGRS recommends skipping `skip_frames` frames to let a slower peer catch up.
+    WaitRecommendation { skip_frames: u32 },
+}
@@ -0,0 +1,480 @@
+//! The `Stage` that drives the GGRS rollback loop: stepping the active session forward at a
+//! fixed rate, and saving/loading snapshots of all registered `Rollback` state as GGRS requests.
+
+use std::{
+    collections::{HashMap, HashSet},
+    time::Instant,
+};
+
+use bevy::{ecs::system::BoxedSystem, prelude::*, reflect::TypeRegistryArc};
+use ggrs::{Frame, GGRSError, GGRSRequest, P2PSession, P2PSpectatorSession, PlayerHandle, SyncTestSession};
+
+use crate::{
+    event::GgrsEvent,
+    world_snapshot::{self, WorldSnapshot},
+    Rollback, RollbackIdProvider,
+};
+
+/// How many past frames we keep a [`FrameState`] around for, so a `LoadGameState` request for an
+/// older frame can still be served. GGRS never asks for anything further back than its
+/// prediction window, so this comfortably covers it.
+const MAX_SNAPSHOT_FRAMES: usize = 128;
+
+/// A custom save/load hook for a resource that can't go through the reflection path, e.g. a
+/// third-party resource that isn't `Reflect`. Registered via
+/// [`crate::GGRSApp::register_rollback_resource_with`].
+pub(crate) struct RollbackResource {
+    save: Box<dyn Fn(&World) -> Vec<u8> + Send + Sync>,
+    load: Box<dyn Fn(&mut World, &[u8]) + Send + Sync>,
+}
+
+impl RollbackResource {
+    pub(crate) fn new<T: Resource>(
+        save: impl Fn(&T) -> Vec<u8> + Send + Sync + 'static,
+        load: impl Fn(&[u8]) -> T + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            save: Box::new(move |world| {
+                let resource = world
+                    .get_resource::<T>()
+                    .expect("tried to save a rollback resource that was never inserted");
+                save(resource)
+            }),
+            load: Box::new(move |world, bytes| {
+                world.insert_resource(load(bytes));
+            }),
+        }
+    }
+}
+
+/// The `Rollback`-component snapshot of a frame, the serialized bytes of every
+/// [`RollbackResource`] at that same frame (in registration order), and the
+/// [`RollbackIdProvider`]'s state at that point (see [`RollbackIdProvider::free_id`] for why it
+/// has to travel with the rest of the snapshot).
+#[derive(Default)]
+struct FrameState {
+    world: WorldSnapshot,
+    resources: Vec<Vec<u8>>,
+    id_provider: RollbackIdProvider,
+}
+
+/// Inserted into the `World` to tell the [`GGRSStage`] to drop its current session state.
+/// Used by [`crate::CommandsExt::stop_session`].
+pub(crate) struct GGRSStageResetSession;
+
+/// Emitted whenever GGRS reports that our local checksum for a confirmed frame disagrees with a
+/// remote peer's checksum for that same frame, so games can surface a "desync detected" UI
+/// instead of silently diverging.
+pub struct GgrsDesyncEvent {
+    pub frame: Frame,
+    pub local_checksum: u128,
+    pub remote_checksum: u128,
+}
+
+pub(crate) struct GGRSStage {
+    schedule: Schedule,
+    pub(crate) type_registry: TypeRegistryArc,
+    pub(crate) input_system: Option<BoxedSystem<PlayerHandle, Vec<u8>>>,
+    update_frequency: u32,
+    frame_accumulator: f64,
+    last_update: Instant,
+    snapshots: HashMap<Frame, FrameState>,
+    pub(crate) resources: Vec<RollbackResource>,
+    /// The frame currently being simulated, mirrored into the `GgrsFrame` resource. Tracked
+    /// separately from `frame_accumulator` since it must jump backwards on a `LoadGameState`
+    /// during rollback re-simulation. Starts at `-1` to match GGRS's own 0-indexed frame
+    /// numbering: the first `AdvanceFrame` request increments this to `0` before running the
+    /// schedule, so `GgrsFrame` reports the same frame number GGRS saves that state under.
+    current_frame: Frame,
+    /// Persistent `Rollback` id -> `Entity` mapping, so a respawn after a rollback gets a fresh
+    /// `Entity` while other systems can still resolve the same stable id.
+    id_map: HashMap<u32, Entity>,
+}
+
+impl GGRSStage {
+    pub(crate) fn new() -> Self {
+        Self {
+            schedule: Schedule::default(),
+            type_registry: TypeRegistryArc::default(),
+            input_system: None,
+            update_frequency: 60,
+            frame_accumulator: 0.0,
+            last_update: Instant::now(),
+            snapshots: HashMap::new(),
+            resources: Vec::new(),
+            current_frame: -1,
+            id_map: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn set_schedule(&mut self, schedule: Schedule) -> &mut Self {
+        self.schedule = schedule;
+        self
+    }
+
+    pub(crate) fn set_update_frequency(&mut self, update_frequency: u32) -> &mut Self {
+        self.update_frequency = update_frequency;
+        self
+    }
+
+    /// How many simulation steps are due since the last call, given `update_frequency`.
+    fn frames_to_run(&mut self) -> u32 {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_update).as_secs_f64();
+        self.last_update = now;
+
+        self.frame_accumulator += elapsed * self.update_frequency as f64;
+        let frames = self.frame_accumulator.floor();
+        self.frame_accumulator -= frames;
+        frames as u32
+    }
+
+    fn local_input(&mut self, handle: PlayerHandle, world: &mut World) -> Vec<u8> {
+        match &mut self.input_system {
+            Some(system) => {
+                let input = system.run(handle, world);
+                system.apply_buffers(world);
+                input
+            }
+            None => Vec::new(),
+        }
+    }
+
+    fn handle_requests(&mut self, requests: Vec<GGRSRequest>, world: &mut World) {
+        for request in requests {
+            match request {
+                GGRSRequest::SaveGameState { cell, frame } => self.save_world(cell, frame, world),
+                GGRSRequest::LoadGameState { cell, .. } => self.load_world(cell, world),
+                GGRSRequest::AdvanceFrame { inputs } => self.advance_frame(inputs, world),
+            }
+        }
+    }
+
+    fn save_world(&mut self, cell: ggrs::GameStateCell, frame: Frame, world: &mut World) {
+        let registry = self.type_registry.read();
+        let world_snapshot = WorldSnapshot::from_world(world, &registry);
+        drop(registry);
+
+        let mut checksum = world_snapshot.checksum;
+        let resources: Vec<Vec<u8>> = self.resources.iter().map(|res| (res.save)(world)).collect();
+        // fold the custom-serialized resources (e.g. a physics world) into the same checksum, so
+        // a desync that lives entirely in one of those still trips GGRS's comparison
+        for (index, bytes) in resources.iter().enumerate() {
+            world_snapshot::fold_into_checksum(&mut checksum, index, bytes);
+        }
+
+        let id_provider = world.get_resource::<RollbackIdProvider>().cloned().unwrap_or_default();
+
+        // GGRS only needs the checksum for its own desync comparison; the component data stays
+        // on our side and never has to round-trip through the cell's byte buffer.
+        cell.save(frame, None, Some(checksum));
+
+        self.snapshots.insert(
+            frame,
+            FrameState {
+                world: world_snapshot,
+                resources,
+                id_provider,
+            },
+        );
+        self.snapshots
+            .retain(|&saved_frame, _| frame - saved_frame < MAX_SNAPSHOT_FRAMES as i32);
+    }
+
+    fn load_world(&mut self, cell: ggrs::GameStateCell, world: &mut World) {
+        let state = cell.load();
+        let frame_state = self
+            .snapshots
+            .get(&state.frame)
+            .expect("GGRS requested a load for a frame we never saved a snapshot for");
+
+        let registry = self.type_registry.read();
+        frame_state.world.write_to_world(world, &registry, &mut self.id_map);
+        drop(registry);
+
+        for (resource, bytes) in self.resources.iter().zip(frame_state.resources.iter()) {
+            (resource.load)(world, bytes);
+        }
+        // rewinds RollbackIdProvider's bookkeeping right alongside everything else, discarding
+        // whatever a now-abandoned misprediction allocated or freed
+        world.insert_resource(frame_state.id_provider.clone());
+
+        // a load always rewinds us to the frame that was saved, whether that's the first time
+        // through or a rollback re-simulation
+        self.current_frame = state.frame;
+        world.insert_resource(crate::GgrsFrame(self.current_frame));
+    }
+
+    fn advance_frame(&mut self, inputs: Vec<ggrs::GameInput>, world: &mut World) {
+        self.current_frame += 1;
+        world.insert_resource(crate::GgrsFrame(self.current_frame));
+
+        let ids_before = live_rollback_ids(world);
+
+        world.insert_resource(inputs);
+        self.schedule.run(world);
+        world.remove_resource::<Vec<ggrs::GameInput>>();
+
+        // ids despawned by this frame's own schedule run, freed deterministically (see
+        // RollbackIdProvider::free_id), in sorted order so every peer frees them identically
+        let mut freed: Vec<u32> = ids_before.difference(&live_rollback_ids(world)).copied().collect();
+        freed.sort_unstable();
+        if let Some(mut id_provider) = world.get_resource_mut::<RollbackIdProvider>() {
+            for rollback_id in freed {
+                id_provider.free_id(rollback_id);
+            }
+        }
+    }
+
+    fn run_synctest(&mut self, world: &mut World) {
+        for _ in 0..self.frames_to_run() {
+            let mut session = match world.remove_resource::<SyncTestSession>() {
+                Some(session) => session,
+                None => return,
+            };
+
+            let inputs: Vec<Vec<u8>> = (0..session.num_players())
+                .map(|handle| self.local_input(handle as PlayerHandle, world))
+                .collect();
+
+            let result = session.advance_frame(&inputs);
+            world.insert_resource(session);
+
+            match result {
+                Ok(requests) => self.handle_requests(requests, world),
+                Err(error) => {
+                    warn!("GGRS synctest session returned an error: {:?}", error);
+                    break;
+                }
+            }
+        }
+    }
+
+    fn run_p2p(&mut self, world: &mut World) {
+        for _ in 0..self.frames_to_run() {
+            let mut session = match world.remove_resource::<P2PSession>() {
+                Some(session) => session,
+                None => return,
+            };
+
+            let local_handle = session.local_player_handle();
+            let input = self.local_input(local_handle, world);
+            let result = session.advance_frame(local_handle, &input);
+
+            forward_session_events(session.events(), world);
+            world.insert_resource(session);
+
+            match result {
+                Ok(requests) => self.handle_requests(requests, world),
+                Err(GGRSError::PredictionThreshold) => break,
+                Err(error) => {
+                    warn!("GGRS returned an error advancing the frame: {:?}", error);
+                    break;
+                }
+            }
+        }
+    }
+
+    fn run_spectator(&mut self, world: &mut World) {
+        for _ in 0..self.frames_to_run() {
+            let mut session = match world.remove_resource::<P2PSpectatorSession>() {
+                Some(session) => session,
+                None => return,
+            };
+
+            let result = session.advance_frame();
+
+            forward_session_events(session.events(), world);
+            world.insert_resource(session);
+
+            match result {
+                Ok(requests) => self.handle_requests(requests, world),
+                Err(GGRSError::PredictionThreshold) => break,
+                Err(error) => {
+                    warn!("GGRS spectator session returned an error: {:?}", error);
+                    break;
+                }
+            }
+        }
+    }
+}
+
+impl Stage for GGRSStage {
+    fn run(&mut self, world: &mut World) {
+        if world.remove_resource::<GGRSStageResetSession>().is_some() {
+            self.last_update = Instant::now();
+            self.frame_accumulator = 0.0;
+            self.snapshots.clear();
+            self.current_frame = -1;
+            self.id_map.clear();
+            world.insert_resource(crate::GgrsFrame::default());
+        }
+
+        if world.contains_resource::<SyncTestSession>() {
+            self.run_synctest(world);
+        } else if world.contains_resource::<P2PSession>() {
+            self.run_p2p(world);
+        } else if world.contains_resource::<P2PSpectatorSession>() {
+            self.run_spectator(world);
+        }
+    }
+}
+
+/// Drains a session's pending GGRS events, translating each one into a [`GgrsEvent`] or
+/// [`GgrsDesyncEvent`] and handing it to the matching `EventWriter`'s queue.
+fn forward_session_events(events: impl Iterator<Item = ggrs::GGRSEvent>, world: &mut World) {
+    for event in events {
+        match event {
+            ggrs::GGRSEvent::Synchronizing {
+                player_handle,
+                total,
+                count,
+            } => send_event(
+                world,
+                GgrsEvent::Synchronizing {
+                    player: player_handle,
+                    total,
+                    count,
+                },
+            ),
+            ggrs::GGRSEvent::Synchronized { player_handle } => send_event(
+                world,
+                GgrsEvent::Synchronized {
+                    player: player_handle,
+                },
+            ),
+            ggrs::GGRSEvent::Disconnected { player_handle } => send_event(
+                world,
+                GgrsEvent::Disconnected {
+                    player: player_handle,
+                },
+            ),
+            ggrs::GGRSEvent::NetworkInterrupted {
+                player_handle,
+                disconnect_timeout,
+            } => send_event(
+                world,
+                GgrsEvent::NetworkInterrupted {
+                    player: player_handle,
+                    disconnect_timeout,
+                },
+            ),
+            ggrs::GGRSEvent::NetworkResumed { player_handle } => send_event(
+                world,
+                GgrsEvent::NetworkResumed {
+                    player: player_handle,
+                },
+            ),
+            ggrs::GGRSEvent::WaitRecommendation { skip_frames } => {
+                send_event(world, GgrsEvent::WaitRecommendation { skip_frames })
+            }
+            ggrs::GGRSEvent::DesyncDetected {
+                frame,
+                local_checksum,
+                remote_checksum,
+            } => send_event(
+                world,
+                GgrsDesyncEvent {
+                    frame,
+                    local_checksum,
+                    remote_checksum,
+                },
+            ),
+        }
+    }
+}
+
+fn send_event<T: Send + Sync + 'static>(world: &mut World, event: T) {
+    if let Some(mut events) = world.get_resource_mut::<Events<T>>() {
+        events.send(event);
+    }
+}
+
+/// The set of `Rollback` ids currently alive in `world`, used by [`GGRSStage::advance_frame`] to
+/// detect which ids a frame's schedule run despawned.
+fn live_rollback_ids(world: &mut World) -> HashSet<u32> {
+    world.query::<&Rollback>().iter(world).map(Rollback::id).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ggrs::GameStateCell;
+
+    /// Deterministic, frame-keyed despawn schedule driving the test below, standing in for
+    /// ordinary gameplay logic that despawns a `Rollback` entity on some frame.
+    struct DespawnPlan(HashMap<Frame, u32>);
+
+    fn despawn_system(
+        mut commands: Commands,
+        plan: Res<DespawnPlan>,
+        frame: Res<crate::GgrsFrame>,
+        query: Query<(Entity, &Rollback)>,
+    ) {
+        if let Some(&id) = plan.0.get(&frame.0) {
+            for (entity, rollback) in query.iter() {
+                if rollback.id() == id {
+                    commands.entity(entity).despawn();
+                }
+            }
+        }
+    }
+
+    fn new_stage() -> (GGRSStage, World) {
+        let mut world = World::default();
+        world.insert_resource(RollbackIdProvider::default());
+        (GGRSStage::new(), world)
+    }
+
+    /// Advancing through a frame that despawns a `Rollback` entity, rolling back to just before
+    /// it, and re-advancing through the same frame again must free that entity's id exactly
+    /// once, not zero or two times - freeing has to happen deterministically from
+    /// `advance_frame`'s own schedule run (the same for every peer) rather than from
+    /// `load_world`, which only the mispredicting peer calls. A second free (or a missed one)
+    /// here would desync `RollbackIdProvider`'s free-list between peers even though the visible
+    /// entity set matched.
+    #[test]
+    fn rollback_replays_id_recycling_deterministically() {
+        let (mut stage, mut world) = new_stage();
+
+        let (id_a, id_b) = {
+            let mut provider = world.get_resource_mut::<RollbackIdProvider>().unwrap();
+            (provider.next_id(), provider.next_id())
+        };
+        world.spawn().insert(Rollback::new(id_a));
+        world.spawn().insert(Rollback::new(id_b));
+
+        // the despawn plan is keyed by the id actually allocated above, not a hardcoded id, so
+        // the test doesn't depend on RollbackIdProvider's allocation order
+        let mut plan = HashMap::new();
+        plan.insert(1, id_a);
+        world.insert_resource(DespawnPlan(plan));
+        let mut schedule = Schedule::default();
+        schedule.add_stage("update", SystemStage::parallel().with_system(despawn_system.system()));
+        stage.set_schedule(schedule);
+
+        // frame 0: both entities alive, nothing freed yet
+        stage.advance_frame(Vec::new(), &mut world);
+        stage.save_world(GameStateCell::default(), 0, &mut world);
+
+        // frame 1: the plan despawns id_a, which advance_frame must recycle deterministically
+        stage.advance_frame(Vec::new(), &mut world);
+        let straight_through_ids = live_rollback_ids(&mut world);
+        let straight_through_provider = world.get_resource::<RollbackIdProvider>().unwrap().clone();
+
+        // roll back to the frame-0 snapshot and replay frame 1 again
+        let frame_0_cell = {
+            let snapshot = stage.snapshots.get(&0).expect("frame 0 was saved above");
+            let cell = GameStateCell::default();
+            cell.save(0, None, Some(snapshot.world.checksum));
+            cell
+        };
+        stage.load_world(frame_0_cell, &mut world);
+        stage.advance_frame(Vec::new(), &mut world);
+
+        assert_eq!(live_rollback_ids(&mut world), straight_through_ids);
+        let replayed_provider = world.get_resource::<RollbackIdProvider>().unwrap();
+        assert_eq!(replayed_provider.free_ids, straight_through_provider.free_ids);
+        assert_eq!(replayed_provider.next_id, straight_through_provider.next_id);
+    }
+}
@@ -8,12 +8,14 @@ use bevy::{
 };
 use ggrs::{P2PSession, P2PSpectatorSession, PlayerHandle, SessionState, SyncTestSession};
 use ggrs_stage::{GGRSStage, GGRSStageResetSession};
-use reflect_resource::ReflectResource;
 
+pub(crate) mod event;
 pub(crate) mod ggrs_stage;
-pub(crate) mod reflect_resource;
 pub(crate) mod world_snapshot;
 
+pub use event::GgrsEvent;
+pub use ggrs_stage::GgrsDesyncEvent;
+
 /// Stage label for the Custom GGRS Stage.
 pub const GGRS_UPDATE: &str = "ggrs_update";
 
@@ -50,16 +52,39 @@ impl Rollback {
     }
 }
 
+/// The frame GGRS is currently simulating, correct even mid-rollback re-simulation. Read this
+/// instead of wall-clock time for anything that needs to be deterministic across replays, e.g.
+/// animation timers, cooldowns, or RNG seeding.
+///
+/// GGRS's own frames are 0-indexed, so the default value is `-1`, meaning "no frame has been
+/// simulated yet" - it stays outside GGRS's valid frame range until the first `AdvanceFrame`
+/// request runs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GgrsFrame(pub ggrs::Frame);
+
+impl Default for GgrsFrame {
+    fn default() -> Self {
+        GgrsFrame(-1)
+    }
+}
+
 /// Provides unique ids for your Rollback components.
 /// When you add the GGRS Plugin, this should be available as a resource.
-#[derive(Default)]
+///
+/// Recycled ids are tracked in a free-list rather than only climbing toward `u32::MAX`; see
+/// [`Self::free_id`] for why freeing one is only ever safe from one specific call site.
+#[derive(Default, Clone)]
 pub struct RollbackIdProvider {
     next_id: u32,
+    free_ids: Vec<u32>,
 }
 
 impl RollbackIdProvider {
     /// Returns an unused, unique id.
     pub fn next_id(&mut self) -> u32 {
+        if let Some(id) = self.free_ids.pop() {
+            return id;
+        }
         if self.next_id == u32::MAX {
             // TODO: do something smart?
             panic!("RollbackIdProvider: u32::MAX has been reached.");
@@ -68,6 +93,18 @@ impl RollbackIdProvider {
         self.next_id += 1;
         ret
     }
+
+    /// Returns an id to the free-list for reuse.
+    ///
+    /// This resource is part of the per-frame rollback snapshot, so a misprediction's
+    /// allocations and frees get undone on rollback along with everything else - but only if
+    /// freeing happens deterministically. Call this only from
+    /// [`crate::ggrs_stage::GGRSStage::advance_frame`]'s own schedule run, which every peer
+    /// replays identically; never from rollback load/save bookkeeping, which is peer-asymmetric
+    /// (only the mispredicting peer reloads).
+    pub(crate) fn free_id(&mut self, id: u32) {
+        self.free_ids.push(id);
+    }
 }
 
 /// Provides all functionality for the GGRS p2p rollback networking library.
@@ -79,6 +116,12 @@ impl Plugin for GGRSPlugin {
         app.add_stage_before(CoreStage::Update, GGRS_UPDATE, GGRSStage::new());
         // insert a rollback id provider
         app.insert_resource(RollbackIdProvider::default());
+        // insert the frame counter systems can read for a deterministic notion of "now"
+        app.insert_resource(GgrsFrame::default());
+        // let games react to a checksum mismatch between peers instead of silently diverging
+        app.add_event::<GgrsDesyncEvent>();
+        // let games drive connection UI off of GGRS's session-lifecycle events
+        app.add_event::<GgrsEvent>();
     }
 }
 
@@ -111,10 +154,17 @@ pub trait GGRSApp {
     where
         T: GetTypeRegistration + Reflect + Default + Component;
 
-    // Inserts a resource in bevy with saving and loading during rollbacks.
-    fn insert_rollback_resource<T>(&mut self, resource: T) -> &mut Self
+    /// Registers a resource for saving and loading during rollbacks via user-provided
+    /// save/load functions. This is the only supported way to roll back a resource - the prior
+    /// `insert_rollback_resource`/reflection-based route was removed as a deliberate breaking API
+    /// change (it never actually saved or loaded anything), not merely patched in place.
+    fn register_rollback_resource_with<T>(
+        &mut self,
+        save: impl Fn(&T) -> Vec<u8> + Send + Sync + 'static,
+        load: impl Fn(&[u8]) -> T + Send + Sync + 'static,
+    ) -> &mut Self
     where
-        T: GetTypeRegistration + Reflect + Default + Component + Resource;
+        T: Resource;
 }
 
 impl GGRSApp for App {
@@ -183,17 +233,27 @@ impl GGRSApp for App {
 
         let registration = registry.get_mut(std::any::TypeId::of::<T>()).unwrap();
         registration.insert(<ReflectComponent as FromType<T>>::from_type());
-        registration.insert(<ReflectResource as FromType<T>>::from_type());
         drop(registry);
 
         self
     }
 
-    fn insert_rollback_resource<T>(&mut self, resource: T) -> &mut Self
+    fn register_rollback_resource_with<T>(
+        &mut self,
+        save: impl Fn(&T) -> Vec<u8> + Send + Sync + 'static,
+        load: impl Fn(&[u8]) -> T + Send + Sync + 'static,
+    ) -> &mut Self
     where
-        T: GetTypeRegistration + Reflect + Default + Component + Resource,
+        T: Resource,
     {
-        self.insert_resource(resource).register_rollback_type::<T>()
+        let ggrs_stage = self
+            .schedule
+            .get_stage_mut::<GGRSStage>(&GGRS_UPDATE)
+            .expect("No GGRSStage found! Did you install the GGRSPlugin?");
+        ggrs_stage
+            .resources
+            .push(ggrs_stage::RollbackResource::new(save, load));
+        self
     }
 }
 
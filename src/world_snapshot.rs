@@ -0,0 +1,307 @@
+//! Captures and restores the state of all `Rollback`-tagged entities in a `World`, and folds
+//! that same state into an order-independent checksum for GGRS's desync detection.
+
+use std::collections::{HashMap, HashSet};
+
+use bevy::{
+    prelude::*,
+    reflect::{ReflectRef, TypeRegistry},
+};
+
+use crate::Rollback;
+
+/// A point-in-time copy of every `Rollback` entity's registered components, plus a checksum
+/// over their content.
+#[derive(Default)]
+pub(crate) struct WorldSnapshot {
+    pub(crate) entities: Vec<RollbackEntity>,
+    /// Order-independent checksum of all entities' reflected component data. Handed to GGRS so
+    /// it can compare confirmed frames across peers and flag a desync.
+    pub(crate) checksum: u128,
+}
+
+pub(crate) struct RollbackEntity {
+    pub(crate) rollback_id: u32,
+    pub(crate) components: Vec<Box<dyn Reflect>>,
+}
+
+impl WorldSnapshot {
+    pub(crate) fn from_world(world: &World, type_registry: &TypeRegistry) -> Self {
+        let mut snapshot = WorldSnapshot::default();
+
+        // sort by rollback id so iteration order - and therefore the checksum - does not
+        // depend on archetype layout, which can differ between peers
+        let mut rollback_ids: Vec<(Entity, u32)> = world
+            .query::<(Entity, &Rollback)>()
+            .iter(world)
+            .map(|(entity, rollback)| (entity, rollback.id()))
+            .collect();
+        rollback_ids.sort_unstable_by_key(|(_, id)| *id);
+
+        for (entity, rollback_id) in rollback_ids {
+            let mut components = Vec::new();
+            for registration in type_registry.iter() {
+                let reflect_component = match registration.data::<ReflectComponent>() {
+                    Some(reflect_component) => reflect_component,
+                    None => continue,
+                };
+                if let Some(component) = reflect_component.reflect(world, entity) {
+                    components.push(component.clone_value());
+                }
+            }
+
+            // combined with XOR so entity *order* cannot change the result; the id is mixed into
+            // the hashed bytes themselves (see component_checksum) rather than applied after, so
+            // an entity whose components happen to serialize to all zeros - the common case for
+            // a default-valued component - still checksums differently per id instead of
+            // collapsing to 0, indistinguishable from that entity not existing at all
+            snapshot.checksum ^= component_checksum(rollback_id, &components) as u128;
+
+            snapshot.entities.push(RollbackEntity {
+                rollback_id,
+                components,
+            });
+        }
+
+        snapshot
+    }
+
+    /// Applies this snapshot's component values back onto the matching `Rollback` entities.
+    ///
+    /// Entities that exist now but aren't part of this snapshot are despawned; entities that are
+    /// part of it but don't exist any more are respawned with a fresh `Entity` but their original
+    /// `Rollback` id (see [`crate::RollbackIdProvider::free_id`] for why freeing the id itself
+    /// isn't this function's job). `id_map` is the stage's persistent id -> entity mapping; it's
+    /// rebuilt from the live world on every call, since ordinary game logic may have despawned a
+    /// `Rollback` entity since the map was last touched.
+    pub(crate) fn write_to_world(
+        &self,
+        world: &mut World,
+        type_registry: &TypeRegistry,
+        id_map: &mut HashMap<u32, Entity>,
+    ) {
+        id_map.clear();
+        for (entity, rollback) in world.query::<(Entity, &Rollback)>().iter(world) {
+            id_map.insert(rollback.id(), entity);
+        }
+
+        let snapshot_ids: HashSet<u32> = self.entities.iter().map(|e| e.rollback_id).collect();
+        let stale_ids: Vec<u32> = id_map
+            .keys()
+            .filter(|id| !snapshot_ids.contains(id))
+            .copied()
+            .collect();
+        for rollback_id in stale_ids {
+            let entity = id_map.remove(&rollback_id).unwrap();
+            world.despawn(entity);
+        }
+
+        for rollback_entity in &self.entities {
+            let entity = *id_map.entry(rollback_entity.rollback_id).or_insert_with(|| {
+                world
+                    .spawn()
+                    .insert(Rollback::new(rollback_entity.rollback_id))
+                    .id()
+            });
+
+            for component in &rollback_entity.components {
+                let registration = match type_registry.get_with_name(component.type_name()) {
+                    Some(registration) => registration,
+                    None => continue,
+                };
+                let reflect_component = match registration.data::<ReflectComponent>() {
+                    Some(reflect_component) => reflect_component,
+                    None => continue,
+                };
+
+                if reflect_component.reflect(world, entity).is_some() {
+                    reflect_component.apply_component(world, entity, component.as_ref());
+                } else {
+                    reflect_component.add_component(world, entity, component.as_ref());
+                }
+            }
+        }
+    }
+}
+
+/// Folds arbitrary serialized bytes - e.g. a `RollbackResource`'s save output - into an existing
+/// checksum accumulator the same way a reflected entity's components are folded in
+/// [`WorldSnapshot::from_world`]. Lets [`crate::ggrs_stage::GGRSStage::save_world`] include
+/// custom-serialized resources (a physics world, say) in the desync checksum alongside reflected
+/// components, so a divergence living entirely in one of those still trips GGRS's comparison.
+/// Resources are registered in a fixed order rather than being order-independent like entities,
+/// so `index` (their position in that registration order) stands in for the entity id.
+pub(crate) fn fold_into_checksum(checksum: &mut u128, index: usize, bytes: &[u8]) {
+    *checksum ^= id_mixed_checksum(index as u32, bytes) as u128;
+}
+
+/// Folds a single entity's reflected components through a Fletcher-16 running sum, with `id`
+/// mixed into the hashed bytes rather than applied afterward - see the call site in
+/// [`WorldSnapshot::from_world`] for why that distinction matters.
+fn component_checksum(id: u32, components: &[Box<dyn Reflect>]) -> u32 {
+    let mut buffer = Vec::new();
+    for component in components {
+        reflect_to_bytes(component.as_ref(), &mut buffer);
+    }
+    id_mixed_checksum(id, &buffer)
+}
+
+/// Fletcher-16 over `id`'s bytes followed by `bytes`, so that even an empty or all-zero `bytes`
+/// still hashes differently per `id` - folding `id` in afterward (e.g. multiplying the hash by
+/// it) would leave an all-zero hash at all-zero, indistinguishable from absence. `id` is offset
+/// by one so id `0` - the first id `RollbackIdProvider` ever allocates - still contributes a
+/// non-zero prefix. Fletcher-16's sums are mod 255, so a prefix such as `[255, 0, 0, 0]` (id
+/// `254`) can still sum to 0; bit 16 of the result is therefore always set, keeping it non-zero.
+fn id_mixed_checksum(id: u32, bytes: &[u8]) -> u32 {
+    let mut buffer = Vec::with_capacity(4 + bytes.len());
+    buffer.extend_from_slice(&id.wrapping_add(1).to_le_bytes());
+    buffer.extend_from_slice(bytes);
+    fletcher16(&buffer) as u32 | 1 << 16
+}
+
+/// Walks a reflected value's structure via the existing `Reflect` introspection path, appending
+/// the bytes of every primitive leaf it finds. Unknown leaf types are skipped rather than
+/// guessed at - an incomplete checksum is safer than a misleading one.
+fn reflect_to_bytes(value: &dyn Reflect, buffer: &mut Vec<u8>) {
+    match value.reflect_ref() {
+        ReflectRef::Struct(s) => {
+            for i in 0..s.field_len() {
+                reflect_to_bytes(s.field_at(i).unwrap(), buffer);
+            }
+        }
+        ReflectRef::TupleStruct(s) => {
+            for i in 0..s.field_len() {
+                reflect_to_bytes(s.field(i).unwrap(), buffer);
+            }
+        }
+        ReflectRef::Tuple(t) => {
+            for i in 0..t.field_len() {
+                reflect_to_bytes(t.field(i).unwrap(), buffer);
+            }
+        }
+        ReflectRef::List(list) => {
+            for item in list.iter() {
+                reflect_to_bytes(item, buffer);
+            }
+        }
+        ReflectRef::Array(array) => {
+            for item in array.iter() {
+                reflect_to_bytes(item, buffer);
+            }
+        }
+        ReflectRef::Map(map) => {
+            for (key, val) in map.iter() {
+                reflect_to_bytes(key, buffer);
+                reflect_to_bytes(val, buffer);
+            }
+        }
+        ReflectRef::Value(_) => append_primitive_bytes(value, buffer),
+    }
+}
+
+fn append_primitive_bytes(value: &dyn Reflect, buffer: &mut Vec<u8>) {
+    macro_rules! try_push {
+        ($ty:ty) => {
+            if let Some(v) = value.downcast_ref::<$ty>() {
+                buffer.extend_from_slice(&v.to_le_bytes());
+                return;
+            }
+        };
+    }
+    try_push!(u8);
+    try_push!(u16);
+    try_push!(u32);
+    try_push!(u64);
+    try_push!(u128);
+    try_push!(i8);
+    try_push!(i16);
+    try_push!(i32);
+    try_push!(i64);
+    try_push!(i128);
+    try_push!(f32);
+    try_push!(f64);
+    if let Some(v) = value.downcast_ref::<bool>() {
+        buffer.push(*v as u8);
+        return;
+    }
+    if let Some(v) = value.downcast_ref::<String>() {
+        buffer.extend_from_slice(v.as_bytes());
+    }
+}
+
+/// Fletcher-16: cheap, order-sensitive-within-a-buffer, good enough to catch accidental
+/// divergence without pulling in a CRC crate for it.
+fn fletcher16(data: &[u8]) -> u16 {
+    let (mut sum1, mut sum2) = (0u16, 0u16);
+    for &byte in data {
+        sum1 = (sum1 + byte as u16) % 255;
+        sum2 = (sum2 + sum1) % 255;
+    }
+    (sum2 << 8) | sum1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bevy::reflect::FromType;
+
+    #[derive(Component, Reflect, Default, Clone)]
+    struct Marker(u32);
+
+    fn registry_with_marker() -> TypeRegistry {
+        let mut registry = TypeRegistry::default();
+        registry.register::<Marker>();
+        let registration = registry.get_mut(std::any::TypeId::of::<Marker>()).unwrap();
+        registration.insert(<ReflectComponent as FromType<Marker>>::from_type());
+        registry
+    }
+
+    #[test]
+    fn checksum_is_independent_of_spawn_order() {
+        let registry = registry_with_marker();
+
+        let mut world_a = World::default();
+        world_a.spawn().insert(Rollback::new(1)).insert(Marker(10));
+        world_a.spawn().insert(Rollback::new(0)).insert(Marker(20));
+
+        let mut world_b = World::default();
+        world_b.spawn().insert(Rollback::new(0)).insert(Marker(20));
+        world_b.spawn().insert(Rollback::new(1)).insert(Marker(10));
+
+        let checksum_a = WorldSnapshot::from_world(&world_a, &registry).checksum;
+        let checksum_b = WorldSnapshot::from_world(&world_b, &registry).checksum;
+        assert_eq!(checksum_a, checksum_b);
+    }
+
+    #[test]
+    fn checksum_changes_with_component_content() {
+        let registry = registry_with_marker();
+
+        let mut world = World::default();
+        world.spawn().insert(Rollback::new(0)).insert(Marker(10));
+        let before = WorldSnapshot::from_world(&world, &registry).checksum;
+
+        world.query::<&mut Marker>().iter_mut(&mut world).next().unwrap().0 = 11;
+        let after = WorldSnapshot::from_world(&world, &registry).checksum;
+
+        assert_ne!(before, after);
+    }
+
+    /// Regression test: a checksum that folds the id in after hashing (e.g. multiplying the
+    /// per-entity hash by it) collapses an all-zero-component entity to checksum 0 regardless of
+    /// id, indistinguishable from that entity not existing - so peer A spawning an extra
+    /// zero-valued entity peer B never spawns would pass GGRS's desync comparison undetected.
+    #[test]
+    fn all_zero_entity_is_distinguishable_from_absence() {
+        let registry = registry_with_marker();
+
+        let empty_world = World::default();
+        let empty_checksum = WorldSnapshot::from_world(&empty_world, &registry).checksum;
+
+        let mut world_with_zero_entity = World::default();
+        world_with_zero_entity.spawn().insert(Rollback::new(0)).insert(Marker(0));
+        let zero_entity_checksum = WorldSnapshot::from_world(&world_with_zero_entity, &registry).checksum;
+
+        assert_ne!(empty_checksum, zero_entity_checksum);
+    }
+}